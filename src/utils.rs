@@ -1,25 +1,91 @@
 /// src/utils.rs - Enhanced centralized logging and utilities with model loading detection
 
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::{self, Write};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
 use std::time::{Duration, Instant};
 use warp::reject::Reject;
+use warp::Filter;
 
 use crate::constants::*;
 
 // Global logging state
 static LOGGING_ENABLED: AtomicBool = AtomicBool::new(true);
 
+// Global minimum log level (stored as LogLevel::as_u8()), defaults to Info
+static GLOBAL_LOG_LEVEL: AtomicU8 = AtomicU8::new(2);
+
+// Whether log lines are emitted as structured JSON instead of plaintext
+static LOG_JSON_MODE: AtomicBool = AtomicBool::new(false);
+
+// Counter used to make correlation IDs unique within a process run
+static CORR_ID_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// Per-module log level overrides, e.g. "handlers::chat" => LogLevel::Debug
+static MODULE_LOG_LEVELS: OnceLock<RwLock<HashMap<String, LogLevel>>> = OnceLock::new();
+
+fn module_levels() -> &'static RwLock<HashMap<String, LogLevel>> {
+    MODULE_LOG_LEVELS.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
 // Thread-local string buffer for reuse
 thread_local! {
     pub static STRING_BUFFER: RefCell<String> = RefCell::new(String::with_capacity(get_runtime_config().string_buffer_size));
 }
 
-/// Initialize global logger
-pub fn init_global_logger(enabled: bool) {
+/// Logging verbosity level, ordered from most to least verbose.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    fn as_str(&self) -> &'static str {
+        match self {
+            LogLevel::Trace => "TRACE",
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Info => "INFO",
+            LogLevel::Warn => "WARN",
+            LogLevel::Error => "ERROR",
+        }
+    }
+
+    fn as_u8(&self) -> u8 {
+        *self as u8
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => LogLevel::Trace,
+            1 => LogLevel::Debug,
+            2 => LogLevel::Info,
+            3 => LogLevel::Warn,
+            _ => LogLevel::Error,
+        }
+    }
+}
+
+/// Initialize the global logger with a minimum level and output mode.
+///
+/// `json_output` selects the structured `{"ts","level","corr_id","op","msg"}`
+/// format used by log ingestion pipelines; otherwise the existing plaintext
+/// format (backed by the reusable `STRING_BUFFER`) is used.
+pub fn init_logger(enabled: bool, level: LogLevel, json_output: bool) {
     LOGGING_ENABLED.store(enabled, Ordering::Relaxed);
+    GLOBAL_LOG_LEVEL.store(level.as_u8(), Ordering::Relaxed);
+    LOG_JSON_MODE.store(json_output, Ordering::Relaxed);
+}
+
+/// Initialize global logger (kept for compatibility - defaults to Info level, plaintext output)
+pub fn init_global_logger(enabled: bool) {
+    init_logger(enabled, LogLevel::Info, false);
 }
 
 /// Check if logging is enabled
@@ -28,6 +94,102 @@ pub fn is_logging_enabled() -> bool {
     LOGGING_ENABLED.load(Ordering::Relaxed)
 }
 
+/// Check if structured JSON logging is enabled
+#[inline]
+pub fn is_json_logging() -> bool {
+    LOG_JSON_MODE.load(Ordering::Relaxed)
+}
+
+/// Override the minimum log level for a single module path (e.g. `module_path!()`),
+/// so noisy paths can be raised or lowered independently of the global level.
+pub fn set_module_log_level(module: &str, level: LogLevel) {
+    if let Ok(mut levels) = module_levels().write() {
+        levels.insert(module.to_string(), level);
+    }
+}
+
+/// Effective log level for a module: its override if set, otherwise the global level.
+fn effective_log_level(module: &str) -> LogLevel {
+    if let Ok(levels) = module_levels().read() {
+        if let Some(level) = levels.get(module) {
+            return *level;
+        }
+    }
+    LogLevel::from_u8(GLOBAL_LOG_LEVEL.load(Ordering::Relaxed))
+}
+
+/// Generate a correlation ID for an inbound request, threaded through the handler
+/// so the upstream LM Studio call and the eventual response can be grouped together.
+pub fn new_correlation_id() -> String {
+    let seq = CORR_ID_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!("{:x}-{:x}", nanos, seq)
+}
+
+/// Escape a string for embedding as a JSON string value.
+fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in sanitize_log_message(s).chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Emit a single leveled log event, in plaintext or structured JSON depending on
+/// the configured output mode. `module` is expected to be `module_path!()` from
+/// the call site so per-module filtering applies to the caller, not to `utils`.
+pub fn log_event(level: LogLevel, module: &str, corr_id: Option<&str>, op: &str, msg: &str) {
+    if !is_logging_enabled() || level < effective_log_level(module) {
+        return;
+    }
+    let corr = corr_id.unwrap_or("-");
+    STRING_BUFFER.with(|buf| {
+        let mut buffer = buf.borrow_mut();
+        buffer.clear();
+        if is_json_logging() {
+            write!(
+                buffer,
+                "{{\"ts\":\"{}\",\"level\":\"{}\",\"corr_id\":\"{}\",\"op\":\"{}\",\"msg\":\"{}\"}}",
+                chrono::Local::now().to_rfc3339(),
+                level.as_str(),
+                json_escape(corr),
+                json_escape(op),
+                json_escape(msg)
+            ).unwrap();
+        } else {
+            write!(
+                buffer,
+                "[{}] {} [{}] {}: {}",
+                chrono::Local::now().format("%H:%M:%S"),
+                level.as_str(),
+                corr,
+                sanitize_log_message(op),
+                sanitize_log_message(msg)
+            ).unwrap();
+        }
+        println!("{}", buffer);
+    });
+}
+
+/// Macro for leveled, correlation-aware logging. Captures the call site's
+/// `module_path!()` so per-module log level overrides apply correctly.
+#[macro_export]
+macro_rules! log_leveled {
+    ($level:expr, $corr_id:expr, $op:expr, $msg:expr) => {
+        $crate::utils::log_event($level, module_path!(), $corr_id, $op, $msg)
+    };
+}
+
 /// Centralized logging functions - use these throughout the application
 
 /// Log informational message
@@ -120,6 +282,8 @@ pub struct ProxyError {
     pub message: String,
     pub status_code: u16,
     kind: ProxyErrorKind,
+    openai_compat: bool,
+    retry_after_secs: Option<u64>,
 }
 
 #[derive(Debug, Clone)]
@@ -131,6 +295,7 @@ enum ProxyErrorKind {
     NotImplemented,
     LMStudioUnavailable,
     ModelLoading,
+    RateLimited,
     Custom,
 }
 
@@ -141,6 +306,8 @@ impl ProxyError {
             message,
             status_code,
             kind: ProxyErrorKind::Custom,
+            openai_compat: false,
+            retry_after_secs: None,
         }
     }
 
@@ -150,6 +317,8 @@ impl ProxyError {
             message: message.to_string(),
             status_code: 500,
             kind: ProxyErrorKind::InternalServerError,
+            openai_compat: false,
+            retry_after_secs: None,
         }
     }
 
@@ -159,6 +328,8 @@ impl ProxyError {
             message: message.to_string(),
             status_code: 400,
             kind: ProxyErrorKind::BadRequest,
+            openai_compat: false,
+            retry_after_secs: None,
         }
     }
 
@@ -168,6 +339,8 @@ impl ProxyError {
             message: message.to_string(),
             status_code: 404,
             kind: ProxyErrorKind::NotFound,
+            openai_compat: false,
+            retry_after_secs: None,
         }
     }
 
@@ -177,15 +350,20 @@ impl ProxyError {
             message: message.to_string(),
             status_code: 501,
             kind: ProxyErrorKind::NotImplemented,
+            openai_compat: false,
+            retry_after_secs: None,
         }
     }
 
     /// Create request cancelled error
     pub fn request_cancelled() -> Self {
+        record_cancellation();
         Self {
             message: ERROR_CANCELLED.to_string(),
             status_code: 499,
             kind: ProxyErrorKind::RequestCancelled,
+            openai_compat: false,
+            retry_after_secs: None,
         }
     }
 
@@ -195,6 +373,8 @@ impl ProxyError {
             message: message.to_string(),
             status_code: 503,
             kind: ProxyErrorKind::LMStudioUnavailable,
+            openai_compat: false,
+            retry_after_secs: None,
         }
     }
 
@@ -204,6 +384,19 @@ impl ProxyError {
             message: message.to_string(),
             status_code: 503,
             kind: ProxyErrorKind::ModelLoading,
+            openai_compat: false,
+            retry_after_secs: None,
+        }
+    }
+
+    /// Create rate limited error (HTTP 429), carrying a `Retry-After` hint in seconds.
+    pub fn rate_limited(message: &str, retry_after_secs: u64) -> Self {
+        Self {
+            message: message.to_string(),
+            status_code: 429,
+            kind: ProxyErrorKind::RateLimited,
+            openai_compat: false,
+            retry_after_secs: Some(retry_after_secs),
         }
     }
 
@@ -221,6 +414,74 @@ impl ProxyError {
     pub fn is_model_loading(&self) -> bool {
         matches!(self.kind, ProxyErrorKind::ModelLoading) || is_model_loading_error(&self.message)
     }
+
+    /// Mark this error as coming from an OpenAI-compatible endpoint, so
+    /// `into_response` renders the `{"error":{"message","type","code"}}` shape
+    /// instead of Ollama's `{"error": "..."}` shape.
+    pub fn as_openai_compat(mut self) -> Self {
+        self.openai_compat = true;
+        self
+    }
+
+    /// Stable machine-readable error type used in the OpenAI-compatible error body.
+    fn error_type(&self) -> &'static str {
+        match self.kind {
+            ProxyErrorKind::RequestCancelled => "request_cancelled",
+            ProxyErrorKind::InternalServerError => "internal_server_error",
+            ProxyErrorKind::BadRequest => "invalid_request_error",
+            ProxyErrorKind::NotFound => "not_found_error",
+            ProxyErrorKind::NotImplemented => "not_implemented",
+            ProxyErrorKind::LMStudioUnavailable => "service_unavailable",
+            ProxyErrorKind::ModelLoading => "model_loading",
+            ProxyErrorKind::RateLimited => "rate_limit_exceeded",
+            ProxyErrorKind::Custom => "api_error",
+        }
+    }
+
+    /// Render this error into the HTTP response Ollama/OpenAI clients expect:
+    /// `{"error": "..."}` by default, or the OpenAI-compat
+    /// `{"error":{"message","type","code"}}` shape when `as_openai_compat` was set.
+    /// Adds a `Retry-After` header for rate-limited errors.
+    pub fn into_response(&self) -> warp::reply::Response {
+        use warp::Reply;
+
+        let status = warp::http::StatusCode::from_u16(self.status_code)
+            .unwrap_or(warp::http::StatusCode::INTERNAL_SERVER_ERROR);
+
+        let reply: warp::reply::Response = if self.openai_compat {
+            let body = serde_json::json!({
+                "error": {
+                    "message": self.message,
+                    "type": self.error_type(),
+                    "code": self.status_code,
+                }
+            });
+            warp::reply::with_status(warp::reply::json(&body), status).into_response()
+        } else {
+            let body = serde_json::json!({ "error": self.message });
+            warp::reply::with_status(warp::reply::json(&body), status).into_response()
+        };
+
+        match self.retry_after_secs {
+            Some(secs) => warp::reply::with_header(reply, "Retry-After", secs.to_string()).into_response(),
+            None => reply,
+        }
+    }
+}
+
+/// Single `warp::recover` entry point so every handler renders errors the same way.
+/// Catches rejected `ProxyError`s (via `warp::reject::custom`) and warp's own
+/// "not found" rejection, rendering both through `ProxyError::into_response`.
+pub async fn handle_rejection(
+    err: warp::Rejection,
+) -> Result<warp::reply::Response, std::convert::Infallible> {
+    if let Some(proxy_err) = err.find::<ProxyError>() {
+        return Ok(proxy_err.into_response());
+    }
+    if err.is_not_found() {
+        return Ok(ProxyError::not_found("resource not found").into_response());
+    }
+    Ok(ProxyError::internal_server_error("unhandled rejection").into_response())
 }
 
 impl fmt::Display for ProxyError {
@@ -305,6 +566,194 @@ pub fn classify_model_loading_error(message: &str) -> ModelLoadingErrorType {
     }
 }
 
+/// Three-state circuit breaker state, exposed for the logging/metrics layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Requests flow normally; failures are counted in a sliding window.
+    Closed,
+    /// Requests short-circuit to `ProxyError::lm_studio_unavailable` without hitting the backend.
+    Open,
+    /// Cooldown elapsed; a limited number of trial requests are allowed through.
+    HalfOpen,
+}
+
+/// Tunables for a `CircuitBreaker`.
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive failures that trip the breaker regardless of failure rate.
+    pub consecutive_failure_threshold: u32,
+    /// Failure rate (0.0-1.0) within `window_size` samples that trips the breaker.
+    pub failure_rate_threshold: f64,
+    /// Number of recent outcomes kept to compute the failure rate.
+    pub window_size: usize,
+    /// Cooldown before an Open breaker allows its first HalfOpen trial request.
+    pub cooldown: Duration,
+    /// Cap on cooldown growth after repeated HalfOpen failures.
+    pub max_cooldown: Duration,
+    /// Trial requests allowed through while HalfOpen.
+    pub half_open_trial_requests: u32,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            consecutive_failure_threshold: 5,
+            failure_rate_threshold: 0.5,
+            window_size: 20,
+            cooldown: Duration::from_secs(5),
+            max_cooldown: Duration::from_secs(60),
+            half_open_trial_requests: 1,
+        }
+    }
+}
+
+struct CircuitBreakerState {
+    status: CircuitState,
+    window: std::collections::VecDeque<bool>,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    current_cooldown: Duration,
+    half_open_trials_remaining: u32,
+}
+
+/// Tracks LM Studio upstream health over time and short-circuits requests
+/// once the backend looks unhealthy, so a wedged model server doesn't pile
+/// up latency on every inbound request.
+///
+/// `classify_model_loading_error` feeds the decision: `ModelLoading` and
+/// `ServiceUnavailable` classifications count as failures, but a genuine
+/// `ModelNotFound` (a client error, not an upstream health signal) does not.
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    state: std::sync::Mutex<CircuitBreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        let cooldown = config.cooldown;
+        Self {
+            config,
+            state: std::sync::Mutex::new(CircuitBreakerState {
+                status: CircuitState::Closed,
+                window: std::collections::VecDeque::new(),
+                consecutive_failures: 0,
+                opened_at: None,
+                current_cooldown: cooldown,
+                half_open_trials_remaining: 0,
+            }),
+        }
+    }
+
+    /// Current breaker state, advancing Open -> HalfOpen once the cooldown has elapsed.
+    pub fn current_state(&self) -> CircuitState {
+        let mut state = self.state.lock().unwrap();
+        self.maybe_advance_to_half_open(&mut state);
+        state.status
+    }
+
+    fn maybe_advance_to_half_open(&self, state: &mut CircuitBreakerState) {
+        if state.status == CircuitState::Open {
+            if let Some(opened_at) = state.opened_at {
+                if opened_at.elapsed() >= state.current_cooldown {
+                    state.status = CircuitState::HalfOpen;
+                    state.half_open_trials_remaining = self.config.half_open_trial_requests;
+                }
+            }
+        }
+    }
+
+    /// Whether a request should be allowed through right now. HalfOpen trial
+    /// slots are consumed as they're handed out so only a bounded number of
+    /// concurrent trial requests reach the backend.
+    pub fn allow_request(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        self.maybe_advance_to_half_open(&mut state);
+        match state.status {
+            CircuitState::Closed => true,
+            CircuitState::Open => false,
+            CircuitState::HalfOpen => {
+                if state.half_open_trials_remaining > 0 {
+                    state.half_open_trials_remaining -= 1;
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    /// Record the outcome of a call that was allowed through.
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.consecutive_failures = 0;
+        push_window(&mut state.window, self.config.window_size, true);
+        if state.status == CircuitState::HalfOpen {
+            state.status = CircuitState::Closed;
+            state.current_cooldown = self.config.cooldown;
+            state.opened_at = None;
+        }
+    }
+
+    fn record_failure(&self, state: &mut CircuitBreakerState) {
+        state.consecutive_failures += 1;
+        push_window(&mut state.window, self.config.window_size, false);
+
+        let failure_rate = failure_rate(&state.window);
+        let should_trip = state.consecutive_failures >= self.config.consecutive_failure_threshold
+            || (state.window.len() >= self.config.window_size && failure_rate >= self.config.failure_rate_threshold);
+
+        match state.status {
+            CircuitState::HalfOpen => {
+                state.current_cooldown = (state.current_cooldown * 2).min(self.config.max_cooldown);
+                state.status = CircuitState::Open;
+                state.opened_at = Some(Instant::now());
+                record_circuit_breaker_trip();
+            }
+            CircuitState::Closed if should_trip => {
+                state.status = CircuitState::Open;
+                state.opened_at = Some(Instant::now());
+                record_circuit_breaker_trip();
+            }
+            _ => {}
+        }
+    }
+
+    /// Feed the result of a backend call into the breaker. Only failures that
+    /// `classify_model_loading_error` attributes to the upstream being
+    /// unhealthy count against the breaker; a `ModelNotFound` is a client
+    /// error and is ignored here.
+    pub fn record_outcome(&self, result: &Result<(), ProxyError>) {
+        match result {
+            Ok(()) => self.record_success(),
+            Err(err) => {
+                let classification = classify_model_loading_error(&err.message);
+                if matches!(
+                    classification,
+                    ModelLoadingErrorType::ModelLoading | ModelLoadingErrorType::ServiceUnavailable
+                ) {
+                    let mut state = self.state.lock().unwrap();
+                    self.record_failure(&mut state);
+                }
+            }
+        }
+    }
+}
+
+fn push_window(window: &mut std::collections::VecDeque<bool>, max_len: usize, success: bool) {
+    window.push_back(success);
+    while window.len() > max_len {
+        window.pop_front();
+    }
+}
+
+fn failure_rate(window: &std::collections::VecDeque<bool>) -> f64 {
+    if window.is_empty() {
+        return 0.0;
+    }
+    let failures = window.iter().filter(|&&success| !success).count();
+    failures as f64 / window.len() as f64
+}
+
 /// Fast duration formatting with better precision
 pub fn format_duration(duration: Duration) -> String {
     let total_nanos = duration.as_nanos();
@@ -318,6 +767,109 @@ pub fn format_duration(duration: Duration) -> String {
     }
 }
 
+/// Tunables for `retry_on_model_loading`.
+#[derive(Debug, Clone)]
+pub struct RetryConfig {
+    /// Base delay for exponential backoff.
+    pub base: Duration,
+    /// Upper bound on the (pre-jitter) backoff delay.
+    pub cap: Duration,
+    /// Maximum number of attempts, including the first.
+    pub max_attempts: u32,
+    /// Response time above which a failed attempt is treated as a probable
+    /// cold-start and retried, per `is_probable_model_loading_by_timing`, even
+    /// when its error does not classify as model loading. Successful attempts
+    /// are returned regardless of how long they took.
+    pub timing_threshold_ms: u128,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            base: Duration::from_millis(200),
+            cap: Duration::from_secs(10),
+            max_attempts: 5,
+            timing_threshold_ms: 3_000,
+        }
+    }
+}
+
+/// Backoff delay with full jitter: `random(0, min(cap, base * 2^attempt))`.
+fn backoff_delay_with_jitter(base: Duration, cap: Duration, attempt: u32) -> Duration {
+    let exp_millis = base.as_millis().saturating_mul(1u128 << attempt.min(32));
+    let capped_millis = exp_millis.min(cap.as_millis());
+    if capped_millis == 0 {
+        return Duration::from_millis(0);
+    }
+    // Lightweight jitter source (no external RNG dependency): the low bits of
+    // the current time are effectively uniform over short intervals.
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let jittered_millis = nanos % (capped_millis + 1);
+    Duration::from_millis(jittered_millis as u64)
+}
+
+/// Transparently retry a call to LM Studio when it looks like the model is
+/// still warming up, instead of surfacing the 503 to the client on the first
+/// attempt. Uses exponential backoff with full jitter between attempts and
+/// aborts promptly (via `check_cancelled!`) if the caller's request is
+/// cancelled while waiting.
+///
+/// `op` is a short operation name used for the per-attempt `log_timed` line
+/// so the total warm-up wait is observable.
+pub async fn retry_on_model_loading<F, Fut, T>(
+    config: &RetryConfig,
+    token: &tokio_util::sync::CancellationToken,
+    op: &str,
+    mut attempt_fn: F,
+) -> Result<T, ProxyError>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, ProxyError>>,
+{
+    let mut attempt: u32 = 0;
+    loop {
+        check_cancelled!(token);
+
+        let start = Instant::now();
+        let result = attempt_fn().await;
+        let elapsed = start.elapsed();
+
+        match result {
+            Ok(value) => return Ok(value),
+            Err(err) => {
+                let should_retry = err.is_model_loading()
+                    || matches!(
+                        classify_model_loading_error(&err.message),
+                        ModelLoadingErrorType::ModelLoading | ModelLoadingErrorType::ModelNotLoaded
+                    )
+                    || is_probable_model_loading_by_timing(elapsed, config.timing_threshold_ms);
+
+                if !should_retry || attempt + 1 >= config.max_attempts {
+                    return Err(err);
+                }
+
+                check_cancelled!(token);
+
+                record_model_loading_retry();
+
+                let delay = backoff_delay_with_jitter(config.base, config.cap, attempt);
+                log_timed(
+                    LOG_PREFIX_WARNING,
+                    &format!("{} retry {}/{} (backoff {})", op, attempt + 1, config.max_attempts, format_duration(delay)),
+                    start,
+                );
+
+                // Wake early on cancellation; the check at the loop top then aborts.
+                let _ = tokio::time::timeout(delay, token.cancelled()).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
 /// Enhanced config validation
 pub fn validate_config(config: &crate::server::Config) -> Result<(), String> {
     if config.listen.parse::<std::net::SocketAddr>().is_err() {
@@ -335,7 +887,10 @@ pub fn validate_config(config: &crate::server::Config) -> Result<(), String> {
 
 /// Check if endpoint requires authentication
 pub fn is_protected_endpoint(path: &str) -> bool {
-    matches!(path, "/admin/*" | "/config/*")
+    const PROTECTED_PREFIXES: [&str; 2] = ["/admin/*", "/config/*"];
+    PROTECTED_PREFIXES
+        .iter()
+        .any(|prefix| path.starts_with(prefix.trim_end_matches('*')))
 }
 
 /// Sanitize log message to prevent log injection
@@ -366,3 +921,788 @@ pub fn extract_client_ip(headers: &warp::http::HeaderMap) -> Option<String> {
     }
     None
 }
+
+/// Fixed latency bucket boundaries, in milliseconds, used by every histogram.
+const LATENCY_BUCKETS_MS: [f64; 11] = [
+    5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0,
+];
+
+const METRIC_SHARDS: usize = 16;
+
+/// A map sharded across `METRIC_SHARDS` mutex-guarded buckets, so concurrent
+/// requests touching different label tuples don't contend on a single lock.
+struct ShardedMap<K, V> {
+    shards: Vec<Mutex<HashMap<K, Arc<V>>>>,
+}
+
+impl<K, V> ShardedMap<K, V>
+where
+    K: Eq + std::hash::Hash + Clone,
+    V: Default,
+{
+    fn new() -> Self {
+        let mut shards = Vec::with_capacity(METRIC_SHARDS);
+        for _ in 0..METRIC_SHARDS {
+            shards.push(Mutex::new(HashMap::new()));
+        }
+        Self { shards }
+    }
+
+    fn shard_index(key: &K) -> usize {
+        use std::hash::Hasher;
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() as usize) % METRIC_SHARDS
+    }
+
+    fn get_or_insert(&self, key: K) -> Arc<V> {
+        let idx = Self::shard_index(&key);
+        let mut shard = self.shards[idx].lock().unwrap();
+        shard.entry(key).or_insert_with(|| Arc::new(V::default())).clone()
+    }
+
+    fn for_each(&self, mut f: impl FnMut(K, &V)) {
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap();
+            for (k, v) in shard.iter() {
+                f(k.clone(), v);
+            }
+        }
+    }
+}
+
+/// Cumulative latency histogram with fixed bucket boundaries, matching the
+/// Prometheus text exposition format.
+struct Histogram {
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        let mut buckets = Vec::with_capacity(LATENCY_BUCKETS_MS.len() + 1);
+        for _ in 0..=LATENCY_BUCKETS_MS.len() {
+            buckets.push(AtomicU64::new(0));
+        }
+        Self {
+            buckets,
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, duration: Duration) {
+        let millis = duration.as_secs_f64() * 1000.0;
+        for (i, bound) in LATENCY_BUCKETS_MS.iter().enumerate() {
+            if millis <= *bound {
+                self.buckets[i].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.buckets[LATENCY_BUCKETS_MS.len()].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram::new()
+    }
+}
+
+static REQUEST_COUNTERS: OnceLock<ShardedMap<(String, String, u16), AtomicU64>> = OnceLock::new();
+static ENDPOINT_HISTOGRAMS: OnceLock<ShardedMap<String, Histogram>> = OnceLock::new();
+static MODEL_HISTOGRAMS: OnceLock<ShardedMap<String, Histogram>> = OnceLock::new();
+static PER_CLIENT_COUNTERS: OnceLock<ShardedMap<String, AtomicU64>> = OnceLock::new();
+
+static MODEL_LOADING_RETRIES: AtomicU64 = AtomicU64::new(0);
+static CIRCUIT_BREAKER_TRIPS: AtomicU64 = AtomicU64::new(0);
+static CANCELLATIONS: AtomicU64 = AtomicU64::new(0);
+static PER_CLIENT_METRICS_ENABLED: AtomicBool = AtomicBool::new(false);
+
+fn request_counters() -> &'static ShardedMap<(String, String, u16), AtomicU64> {
+    REQUEST_COUNTERS.get_or_init(ShardedMap::new)
+}
+
+fn endpoint_histograms() -> &'static ShardedMap<String, Histogram> {
+    ENDPOINT_HISTOGRAMS.get_or_init(ShardedMap::new)
+}
+
+fn model_histograms() -> &'static ShardedMap<String, Histogram> {
+    MODEL_HISTOGRAMS.get_or_init(ShardedMap::new)
+}
+
+fn per_client_counters() -> &'static ShardedMap<String, AtomicU64> {
+    PER_CLIENT_COUNTERS.get_or_init(ShardedMap::new)
+}
+
+/// Enable or disable optional per-client-IP request aggregation. Off by
+/// default to avoid unbounded label cardinality from untrusted client IPs.
+pub fn set_per_client_metrics_enabled(enabled: bool) {
+    PER_CLIENT_METRICS_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Record one completed request: bumps the method/path/status counter and the
+/// per-endpoint (and, if a model was involved, per-model) latency histogram.
+/// Pass `client_headers` to additionally aggregate by client IP when
+/// per-client metrics are enabled via `set_per_client_metrics_enabled`.
+pub fn record_request(
+    method: &str,
+    path: &str,
+    status: u16,
+    duration: Duration,
+    model: Option<&str>,
+    client_headers: Option<&warp::http::HeaderMap>,
+) {
+    request_counters()
+        .get_or_insert((method.to_string(), path.to_string(), status))
+        .fetch_add(1, Ordering::Relaxed);
+
+    endpoint_histograms().get_or_insert(path.to_string()).observe(duration);
+    if let Some(model) = model {
+        model_histograms().get_or_insert(model.to_string()).observe(duration);
+    }
+
+    if PER_CLIENT_METRICS_ENABLED.load(Ordering::Relaxed) {
+        if let Some(ip) = client_headers.and_then(extract_client_ip) {
+            per_client_counters().get_or_insert(ip).fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Record a model-loading retry issued by `retry_on_model_loading`.
+pub fn record_model_loading_retry() {
+    MODEL_LOADING_RETRIES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Record the `CircuitBreaker` tripping from Closed/HalfOpen to Open.
+pub fn record_circuit_breaker_trip() {
+    CIRCUIT_BREAKER_TRIPS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Record a request aborted by client disconnect (`ProxyError::request_cancelled`).
+pub fn record_cancellation() {
+    CANCELLATIONS.fetch_add(1, Ordering::Relaxed);
+}
+
+fn write_histogram_family(out: &mut String, metric_name: &str, label: &str, histograms: &ShardedMap<String, Histogram>) {
+    let _ = writeln!(out, "# HELP {} Latency histogram by {}", metric_name, label);
+    let _ = writeln!(out, "# TYPE {} histogram", metric_name);
+    histograms.for_each(|key, histogram| {
+        for (i, bound) in LATENCY_BUCKETS_MS.iter().enumerate() {
+            let count = histogram.buckets[i].load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{{}=\"{}\",le=\"{}\"}} {}", metric_name, label, key, bound / 1000.0, count);
+        }
+        let inf_count = histogram.buckets[LATENCY_BUCKETS_MS.len()].load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{{}=\"{}\",le=\"+Inf\"}} {}", metric_name, label, key, inf_count);
+        let sum_seconds = histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_sum{{{}=\"{}\"}} {}", metric_name, label, key, sum_seconds);
+        let _ = writeln!(out, "{}_count{{{}=\"{}\"}} {}", metric_name, label, key, histogram.count.load(Ordering::Relaxed));
+    });
+}
+
+/// Render all accumulated metrics in Prometheus text exposition format, for
+/// a `/metrics` route.
+pub fn render_prometheus_metrics() -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(out, "# HELP proxy_requests_total Total requests by method, path and status");
+    let _ = writeln!(out, "# TYPE proxy_requests_total counter");
+    request_counters().for_each(|(method, path, status), count| {
+        let _ = writeln!(
+            out,
+            "proxy_requests_total{{method=\"{}\",path=\"{}\",status=\"{}\"}} {}",
+            method, path, status, count.load(Ordering::Relaxed)
+        );
+    });
+
+    let _ = writeln!(out, "# HELP proxy_model_loading_retries_total Retries issued while a model was warming up");
+    let _ = writeln!(out, "# TYPE proxy_model_loading_retries_total counter");
+    let _ = writeln!(out, "proxy_model_loading_retries_total {}", MODEL_LOADING_RETRIES.load(Ordering::Relaxed));
+
+    let _ = writeln!(out, "# HELP proxy_circuit_breaker_trips_total Times the LM Studio circuit breaker has opened");
+    let _ = writeln!(out, "# TYPE proxy_circuit_breaker_trips_total counter");
+    let _ = writeln!(out, "proxy_circuit_breaker_trips_total {}", CIRCUIT_BREAKER_TRIPS.load(Ordering::Relaxed));
+
+    let _ = writeln!(out, "# HELP proxy_cancellations_total Requests aborted by client disconnect");
+    let _ = writeln!(out, "# TYPE proxy_cancellations_total counter");
+    let _ = writeln!(out, "proxy_cancellations_total {}", CANCELLATIONS.load(Ordering::Relaxed));
+
+    write_histogram_family(&mut out, "proxy_request_duration_seconds", "endpoint", endpoint_histograms());
+    write_histogram_family(&mut out, "proxy_model_duration_seconds", "model", model_histograms());
+
+    if PER_CLIENT_METRICS_ENABLED.load(Ordering::Relaxed) {
+        let _ = writeln!(out, "# HELP proxy_requests_by_client_total Requests by client IP (opt-in)");
+        let _ = writeln!(out, "# TYPE proxy_requests_by_client_total counter");
+        per_client_counters().for_each(|ip, count| {
+            let _ = writeln!(out, "proxy_requests_by_client_total{{client_ip=\"{}\"}} {}", ip, count.load(Ordering::Relaxed));
+        });
+    }
+
+    out
+}
+
+/// Token-bucket limits for `RateLimiter`.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimiterRule {
+    /// Tokens refilled per second.
+    pub rate_per_sec: f64,
+    /// Maximum tokens a bucket can hold (and burst size).
+    pub burst: f64,
+}
+
+impl Default for RateLimiterRule {
+    fn default() -> Self {
+        Self {
+            rate_per_sec: 5.0,
+            burst: 20.0,
+        }
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+    last_access: Instant,
+}
+
+const RATE_LIMIT_SHARDS: usize = 16;
+
+/// Per-client-IP token-bucket rate limiter, keyed by the client IP string the
+/// caller passes to `check` (`rate_limit_filter` documents how it resolves
+/// that IP). Buckets refill lazily on access
+/// (`tokens = min(burst, tokens + elapsed_secs * rate)`). The bucket map is
+/// sharded across `RATE_LIMIT_SHARDS` mutexes, same as `ShardedMap`, so
+/// concurrent clients don't serialize on one lock; idle buckets are swept by
+/// a background task instead of on the request hot path. `is_protected_endpoint`
+/// paths use `protected_rule` instead of the global default.
+pub struct RateLimiter {
+    default_rule: RateLimiterRule,
+    protected_rule: RateLimiterRule,
+    idle_ttl: Duration,
+    buckets: Vec<Mutex<HashMap<String, TokenBucket>>>,
+}
+
+impl RateLimiter {
+    /// Build the limiter and spawn a background task that evicts idle
+    /// buckets every `idle_ttl`, so no request pays for the sweep inline.
+    pub fn new(default_rule: RateLimiterRule, protected_rule: RateLimiterRule, idle_ttl: Duration) -> Arc<Self> {
+        let limiter = Arc::new(Self {
+            default_rule,
+            protected_rule,
+            idle_ttl,
+            buckets: (0..RATE_LIMIT_SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+        });
+
+        let weak = Arc::downgrade(&limiter);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(idle_ttl);
+            loop {
+                ticker.tick().await;
+                match weak.upgrade() {
+                    Some(limiter) => limiter.evict_idle(),
+                    None => break,
+                }
+            }
+        });
+
+        limiter
+    }
+
+    fn rule_for(&self, path: &str) -> RateLimiterRule {
+        if is_protected_endpoint(path) {
+            self.protected_rule
+        } else {
+            self.default_rule
+        }
+    }
+
+    fn shard_index(client_ip: &str) -> usize {
+        use std::hash::{Hash, Hasher};
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        client_ip.hash(&mut hasher);
+        (hasher.finish() as usize) % RATE_LIMIT_SHARDS
+    }
+
+    /// Consume a token for `client_ip` on `path`, or return a 429 `ProxyError`
+    /// carrying a `Retry-After` hint if the bucket is empty.
+    pub fn check(&self, client_ip: &str, path: &str) -> Result<(), ProxyError> {
+        let rule = self.rule_for(path);
+        let now = Instant::now();
+        let mut shard = self.buckets[Self::shard_index(client_ip)].lock().unwrap();
+
+        let bucket = shard.entry(client_ip.to_string()).or_insert_with(|| TokenBucket {
+            tokens: rule.burst,
+            last_refill: now,
+            last_access: now,
+        });
+
+        let elapsed_secs = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed_secs * rule.rate_per_sec).min(rule.burst);
+        bucket.last_refill = now;
+        bucket.last_access = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let retry_after = ((1.0 - bucket.tokens) / rule.rate_per_sec).ceil().max(1.0) as u64;
+            Err(ProxyError::rate_limited("rate limit exceeded", retry_after))
+        }
+    }
+
+    /// Sweep every shard for buckets idle longer than `idle_ttl`, called
+    /// periodically by the background task spawned in `new`.
+    fn evict_idle(&self) {
+        let now = Instant::now();
+        for shard in &self.buckets {
+            let mut shard = shard.lock().unwrap();
+            shard.retain(|_, bucket| now.duration_since(bucket.last_access) < self.idle_ttl);
+        }
+    }
+}
+
+/// Warp filter that enforces `limiter` before the request reaches LM Studio,
+/// rejecting with the `ProxyError` from `RateLimiter::check` so it renders
+/// through `handle_rejection` like every other error.
+///
+/// The client key is the real TCP peer address by default; forwarded-for
+/// headers (`x-forwarded-for`, `x-real-ip`, etc.) are only trusted when
+/// `trust_proxy_headers` is set, since this proxy usually runs with no
+/// reverse proxy in front of it and those headers are trivially spoofable.
+pub fn rate_limit_filter(
+    limiter: Arc<RateLimiter>,
+    trust_proxy_headers: bool,
+) -> impl warp::Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::header::headers_cloned()
+        .and(warp::filters::addr::remote())
+        .and(warp::path::full())
+        .and_then(move |headers: warp::http::HeaderMap, remote: Option<std::net::SocketAddr>, path: warp::path::FullPath| {
+            let limiter = limiter.clone();
+            async move {
+                let client_ip = trust_proxy_headers
+                    .then(|| extract_client_ip(&headers))
+                    .flatten()
+                    .or_else(|| remote.map(|addr| addr.ip().to_string()))
+                    .unwrap_or_else(|| "unknown".to_string());
+                limiter.check(&client_ip, path.as_str()).map_err(warp::reject::custom)
+            }
+        })
+        .untuple_one()
+}
+
+/// Configured set of security/response headers applied uniformly to every
+/// proxied response, built once at startup via `SecurityHeadersBuilder`.
+#[derive(Debug, Clone)]
+pub struct SecurityHeaders {
+    nosniff: bool,
+    frame_options: Option<String>,
+    permissions_policy: Option<String>,
+    cors_origin: Option<String>,
+    cors_methods: Option<String>,
+    cors_headers: Option<String>,
+}
+
+/// Builder for `SecurityHeaders`, configured once at startup from `server::Config`.
+#[derive(Debug, Clone, Default)]
+pub struct SecurityHeadersBuilder {
+    nosniff: bool,
+    frame_options: Option<String>,
+    permissions_policy: Option<String>,
+    cors_origin: Option<String>,
+    cors_methods: Option<String>,
+    cors_headers: Option<String>,
+}
+
+impl SecurityHeadersBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn nosniff(mut self, enabled: bool) -> Self {
+        self.nosniff = enabled;
+        self
+    }
+
+    pub fn frame_options(mut self, value: impl Into<String>) -> Self {
+        self.frame_options = Some(value.into());
+        self
+    }
+
+    pub fn permissions_policy(mut self, value: impl Into<String>) -> Self {
+        self.permissions_policy = Some(value.into());
+        self
+    }
+
+    /// CORS origin allow-list needed by browser-based Ollama clients (e.g.
+    /// `*` or a specific origin). Sets only `Access-Control-Allow-Origin`; the
+    /// method and header lists are set with `cors_methods` and `cors_headers`.
+    pub fn cors_origin(mut self, origin: impl Into<String>) -> Self {
+        self.cors_origin = Some(origin.into());
+        self
+    }
+
+    pub fn cors_methods(mut self, methods: impl Into<String>) -> Self {
+        self.cors_methods = Some(methods.into());
+        self
+    }
+
+    pub fn cors_headers(mut self, headers: impl Into<String>) -> Self {
+        self.cors_headers = Some(headers.into());
+        self
+    }
+
+    pub fn build(self) -> SecurityHeaders {
+        SecurityHeaders {
+            nosniff: self.nosniff,
+            frame_options: self.frame_options,
+            permissions_policy: self.permissions_policy,
+            cors_origin: self.cors_origin,
+            cors_methods: self.cors_methods,
+            cors_headers: self.cors_headers,
+        }
+    }
+}
+
+impl SecurityHeaders {
+    /// Build the header set from `server::Config`. CORS is only enabled when
+    /// `config.cors_allowed_origin` is set; leaving it unset disables
+    /// `Access-Control-Allow-*` headers, and preflight requests then get a bare 204.
+    pub fn from_config(config: &crate::server::Config) -> Self {
+        let mut builder = SecurityHeadersBuilder::new()
+            .nosniff(true)
+            .frame_options("DENY")
+            .permissions_policy("geolocation=(), microphone=(), camera=()");
+
+        if let Some(origin) = &config.cors_allowed_origin {
+            builder = builder
+                .cors_origin(origin.clone())
+                .cors_methods("GET, POST, OPTIONS")
+                .cors_headers("Content-Type, Authorization");
+        }
+
+        builder.build()
+    }
+
+    /// Whether this request/response pair is a WebSocket upgrade or an SSE
+    /// token stream, in which case headers must not be injected since doing
+    /// so can break the connection.
+    fn bypasses(request_headers: &warp::http::HeaderMap, response: &warp::http::Response<warp::hyper::Body>) -> bool {
+        let is_websocket_upgrade = request_headers
+            .get(warp::http::header::CONNECTION)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_lowercase().contains("upgrade"))
+            .unwrap_or(false)
+            && request_headers
+                .get(warp::http::header::UPGRADE)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.eq_ignore_ascii_case("websocket"))
+                .unwrap_or(false);
+
+        let is_sse_stream = response
+            .headers()
+            .get(warp::http::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.contains("text/event-stream"))
+            .unwrap_or(false);
+
+        is_websocket_upgrade || is_sse_stream
+    }
+
+    /// Apply the configured headers to `response`, skipping upgrade/streaming
+    /// exchanges so WebSocket and SSE connections aren't broken. CORS headers
+    /// are withheld from `is_protected_endpoint` paths even when an origin is
+    /// configured, so admin/config endpoints are never exposed cross-origin.
+    pub fn apply(
+        &self,
+        request_headers: &warp::http::HeaderMap,
+        path: &str,
+        mut response: warp::http::Response<warp::hyper::Body>,
+    ) -> warp::http::Response<warp::hyper::Body> {
+        if Self::bypasses(request_headers, &response) {
+            return response;
+        }
+
+        let headers = response.headers_mut();
+        if self.nosniff {
+            headers.insert("X-Content-Type-Options", warp::http::HeaderValue::from_static("nosniff"));
+        }
+        if let Some(frame_options) = &self.frame_options {
+            if let Ok(value) = warp::http::HeaderValue::from_str(frame_options) {
+                headers.insert("X-Frame-Options", value);
+            }
+        }
+        if let Some(policy) = &self.permissions_policy {
+            if let Ok(value) = warp::http::HeaderValue::from_str(policy) {
+                headers.insert("Permissions-Policy", value);
+            }
+        }
+        if !is_protected_endpoint(path) {
+            self.insert_cors_headers(headers);
+        }
+        response
+    }
+
+    fn insert_cors_headers(&self, headers: &mut warp::http::HeaderMap) {
+        if let Some(origin) = &self.cors_origin {
+            if let Ok(value) = warp::http::HeaderValue::from_str(origin) {
+                headers.insert("Access-Control-Allow-Origin", value);
+            }
+        }
+        if let Some(methods) = &self.cors_methods {
+            if let Ok(value) = warp::http::HeaderValue::from_str(methods) {
+                headers.insert("Access-Control-Allow-Methods", value);
+            }
+        }
+        if let Some(allowed_headers) = &self.cors_headers {
+            if let Ok(value) = warp::http::HeaderValue::from_str(allowed_headers) {
+                headers.insert("Access-Control-Allow-Headers", value);
+            }
+        }
+    }
+
+    /// Build the response to a CORS preflight (`OPTIONS`) request: a bare 204
+    /// when CORS is disabled or `path` is protected, otherwise 204 decorated
+    /// with the same `Access-Control-Allow-*` headers `apply` would use.
+    fn preflight_response(&self, path: &str) -> warp::http::Response<warp::hyper::Body> {
+        let mut response = warp::http::Response::builder()
+            .status(warp::http::StatusCode::NO_CONTENT)
+            .body(warp::hyper::Body::empty())
+            .expect("building a bodiless 204 response cannot fail");
+
+        if !is_protected_endpoint(path) {
+            self.insert_cors_headers(response.headers_mut());
+        }
+        response
+    }
+}
+
+/// Wrap `filter` so every response it produces is decorated by
+/// `SecurityHeaders::apply`, with WebSocket/SSE exchanges passed through untouched.
+pub fn with_security_headers<F>(
+    headers: SecurityHeaders,
+    filter: F,
+) -> impl warp::Filter<Extract = (warp::http::Response<warp::hyper::Body>,), Error = warp::Rejection> + Clone
+where
+    F: warp::Filter<Extract = (warp::http::Response<warp::hyper::Body>,), Error = warp::Rejection> + Clone + Send,
+{
+    warp::header::headers_cloned()
+        .and(warp::path::full())
+        .and(filter)
+        .map(move |request_headers: warp::http::HeaderMap, path: warp::path::FullPath, response| {
+            headers.apply(&request_headers, path.as_str(), response)
+        })
+}
+
+/// Answer CORS preflight (`OPTIONS`) requests directly with the configured
+/// `Access-Control-Allow-*` headers, so browser clients issuing non-simple
+/// requests (JSON bodies, custom headers) get a valid preflight response
+/// instead of falling through to routing that never handles `OPTIONS`.
+pub fn cors_preflight_filter(
+    headers: SecurityHeaders,
+) -> impl warp::Filter<Extract = (warp::http::Response<warp::hyper::Body>,), Error = warp::Rejection> + Clone {
+    warp::options()
+        .and(warp::path::full())
+        .map(move |path: warp::path::FullPath| headers.preflight_response(path.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trip_with_failures(breaker: &CircuitBreaker, count: u32) {
+        let err = Err(ProxyError::lm_studio_unavailable("LM Studio is loading the model"));
+        for _ in 0..count {
+            breaker.record_outcome(&err);
+        }
+    }
+
+    #[test]
+    fn circuit_breaker_closed_allows_requests_until_threshold() {
+        let breaker = CircuitBreaker::new(CircuitBreakerConfig {
+            consecutive_failure_threshold: 3,
+            ..Default::default()
+        });
+
+        assert_eq!(breaker.current_state(), CircuitState::Closed);
+        trip_with_failures(&breaker, 2);
+        assert_eq!(breaker.current_state(), CircuitState::Closed);
+        assert!(breaker.allow_request());
+    }
+
+    #[test]
+    fn circuit_breaker_trips_open_at_consecutive_failure_threshold() {
+        let breaker = CircuitBreaker::new(CircuitBreakerConfig {
+            consecutive_failure_threshold: 3,
+            ..Default::default()
+        });
+
+        trip_with_failures(&breaker, 3);
+
+        assert_eq!(breaker.current_state(), CircuitState::Open);
+        assert!(!breaker.allow_request());
+    }
+
+    #[test]
+    fn circuit_breaker_success_resets_consecutive_failures() {
+        let breaker = CircuitBreaker::new(CircuitBreakerConfig {
+            consecutive_failure_threshold: 3,
+            ..Default::default()
+        });
+
+        trip_with_failures(&breaker, 2);
+        breaker.record_outcome(&Ok(()));
+        trip_with_failures(&breaker, 2);
+
+        assert_eq!(breaker.current_state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn circuit_breaker_half_open_after_cooldown_and_recloses_on_success() {
+        let breaker = CircuitBreaker::new(CircuitBreakerConfig {
+            consecutive_failure_threshold: 1,
+            cooldown: Duration::from_millis(0),
+            half_open_trial_requests: 1,
+            ..Default::default()
+        });
+
+        trip_with_failures(&breaker, 1);
+
+        // Zero cooldown means the very next state check advances Open -> HalfOpen.
+        assert_eq!(breaker.current_state(), CircuitState::HalfOpen);
+        assert!(breaker.allow_request());
+
+        breaker.record_outcome(&Ok(()));
+        assert_eq!(breaker.current_state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn circuit_breaker_half_open_failure_doubles_cooldown_and_reopens() {
+        let cooldown = Duration::from_millis(20);
+        let breaker = CircuitBreaker::new(CircuitBreakerConfig {
+            consecutive_failure_threshold: 1,
+            cooldown,
+            max_cooldown: Duration::from_secs(60),
+            half_open_trial_requests: 1,
+            ..Default::default()
+        });
+
+        trip_with_failures(&breaker, 1);
+        std::thread::sleep(cooldown * 2);
+        assert_eq!(breaker.current_state(), CircuitState::HalfOpen);
+
+        // The HalfOpen trial fails too: cooldown doubles and the breaker
+        // reopens immediately, so it must still be Open right after.
+        trip_with_failures(&breaker, 1);
+        assert_eq!(breaker.current_state(), CircuitState::Open);
+        assert_eq!(breaker.state.lock().unwrap().current_cooldown, cooldown * 2);
+    }
+
+    #[test]
+    fn circuit_breaker_ignores_model_not_found_as_a_client_error() {
+        let breaker = CircuitBreaker::new(CircuitBreakerConfig {
+            consecutive_failure_threshold: 1,
+            ..Default::default()
+        });
+
+        breaker.record_outcome(&Err(ProxyError::new("model not found: no such model".to_string(), 404)));
+
+        assert_eq!(breaker.current_state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn backoff_delay_with_jitter_is_bounded_by_the_exponential_value() {
+        let base = Duration::from_millis(200);
+        let cap = Duration::from_secs(10);
+
+        for attempt in 0..6 {
+            let delay = backoff_delay_with_jitter(base, cap, attempt);
+            let exp_millis = base.as_millis() * (1u128 << attempt);
+            let max_expected = exp_millis.min(cap.as_millis());
+            assert!(
+                delay.as_millis() <= max_expected,
+                "attempt {} produced {:?}, expected <= {}ms",
+                attempt,
+                delay,
+                max_expected
+            );
+        }
+    }
+
+    #[test]
+    fn backoff_delay_with_jitter_caps_at_large_attempt_counts() {
+        let base = Duration::from_millis(200);
+        let cap = Duration::from_secs(10);
+
+        // At high attempt counts base * 2^attempt (with the exponent clamped
+        // at 32) is far above `cap`; the result must still be capped at `cap`.
+        let delay = backoff_delay_with_jitter(base, cap, 40);
+
+        assert!(delay <= cap);
+    }
+
+    #[test]
+    fn backoff_delay_with_jitter_is_zero_when_cap_is_zero() {
+        let delay = backoff_delay_with_jitter(Duration::from_millis(200), Duration::from_millis(0), 0);
+
+        assert_eq!(delay, Duration::from_millis(0));
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_allows_up_to_burst_then_limits() {
+        let limiter = RateLimiter::new(
+            RateLimiterRule { rate_per_sec: 1.0, burst: 3.0 },
+            RateLimiterRule { rate_per_sec: 1.0, burst: 3.0 },
+            Duration::from_secs(300),
+        );
+
+        for _ in 0..3 {
+            assert!(limiter.check("1.2.3.4", "/api/chat").is_ok());
+        }
+
+        let err = limiter.check("1.2.3.4", "/api/chat").unwrap_err();
+        assert_eq!(err.status_code, 429);
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_retry_after_hint_is_at_least_one_second() {
+        let limiter = RateLimiter::new(
+            RateLimiterRule { rate_per_sec: 10.0, burst: 1.0 },
+            RateLimiterRule { rate_per_sec: 10.0, burst: 1.0 },
+            Duration::from_secs(300),
+        );
+
+        assert!(limiter.check("9.9.9.9", "/api/chat").is_ok());
+        let err = limiter.check("9.9.9.9", "/api/chat").unwrap_err();
+
+        assert_eq!(err.retry_after_secs, Some(1));
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_tracks_clients_independently() {
+        let limiter = RateLimiter::new(
+            RateLimiterRule { rate_per_sec: 1.0, burst: 1.0 },
+            RateLimiterRule { rate_per_sec: 1.0, burst: 1.0 },
+            Duration::from_secs(300),
+        );
+
+        assert!(limiter.check("1.1.1.1", "/api/chat").is_ok());
+        assert!(limiter.check("1.1.1.1", "/api/chat").is_err());
+        // A different client has its own, untouched bucket.
+        assert!(limiter.check("2.2.2.2", "/api/chat").is_ok());
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_uses_protected_rule_for_protected_paths() {
+        let limiter = RateLimiter::new(
+            RateLimiterRule { rate_per_sec: 100.0, burst: 100.0 },
+            RateLimiterRule { rate_per_sec: 1.0, burst: 1.0 },
+            Duration::from_secs(300),
+        );
+
+        assert!(limiter.check("3.3.3.3", "/admin/users").is_ok());
+        let err = limiter.check("3.3.3.3", "/admin/users").unwrap_err();
+        assert_eq!(err.status_code, 429);
+    }
+}